@@ -0,0 +1,79 @@
+//! Typed dispatch over the raw `(method, params)` pairs the host sends.
+//!
+//! Without this, every plugin re-implements `match method { ... }` with a manual
+//! `serde_json::from_value` on each arm. Instead, `PluginRequest::parse` and
+//! `PluginNotification::parse` do that once and hand back a real enum variant,
+//! the same way Helix turns `PublishDiagnostics::METHOD` into a strongly-typed
+//! `PublishDiagnosticsParams` via `Notification::parse(method, params)`.
+//!
+//! `LapcePlugin`'s default `handle_request`/`handle_notification` implementations
+//! call these and forward the result to `handle_plugin_request`/
+//! `handle_plugin_notification`, so plugins implement the typed methods
+//! directly and never call `parse` themselves.
+
+use psp_types::lsp_types::{
+    notification::{
+        DidChangeConfiguration, DidChangeTextDocument, DidCloseTextDocument,
+        DidOpenTextDocument, DidSaveTextDocument, Notification as LspNotification,
+    },
+    request::{Initialize, Request as LspRequest},
+    DidChangeConfigurationParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, DidSaveTextDocumentParams, InitializeParams,
+};
+use serde_json::Value;
+
+use crate::PluginError;
+
+/// A typed request received from the host, parsed from the raw `(method, params)`
+/// pair `LapcePlugin::handle_request` is handed.
+pub enum PluginRequest {
+    Initialize(InitializeParams),
+    /// A method not (yet) modeled here, so unknown methods aren't silently
+    /// dropped.
+    Other(String, Value),
+}
+
+impl PluginRequest {
+    pub fn parse(method: &str, params: Value) -> Result<Self, PluginError> {
+        Ok(match method {
+            Initialize::METHOD => Self::Initialize(serde_json::from_value(params)?),
+            _ => Self::Other(method.to_string(), params),
+        })
+    }
+}
+
+/// A typed notification received from the host, parsed from the raw
+/// `(method, params)` pair `LapcePlugin::handle_notification` is handed.
+pub enum PluginNotification {
+    DidOpenTextDocument(DidOpenTextDocumentParams),
+    DidSaveTextDocument(DidSaveTextDocumentParams),
+    DidChangeTextDocument(DidChangeTextDocumentParams),
+    DidCloseTextDocument(DidCloseTextDocumentParams),
+    DidChangeConfiguration(DidChangeConfigurationParams),
+    /// A method not (yet) modeled here, so unknown methods aren't silently
+    /// dropped.
+    Other(String, Value),
+}
+
+impl PluginNotification {
+    pub fn parse(method: &str, params: Value) -> Result<Self, PluginError> {
+        Ok(match method {
+            DidOpenTextDocument::METHOD => {
+                Self::DidOpenTextDocument(serde_json::from_value(params)?)
+            }
+            DidSaveTextDocument::METHOD => {
+                Self::DidSaveTextDocument(serde_json::from_value(params)?)
+            }
+            DidChangeTextDocument::METHOD => {
+                Self::DidChangeTextDocument(serde_json::from_value(params)?)
+            }
+            DidCloseTextDocument::METHOD => {
+                Self::DidCloseTextDocument(serde_json::from_value(params)?)
+            }
+            DidChangeConfiguration::METHOD => {
+                Self::DidChangeConfiguration(serde_json::from_value(params)?)
+            }
+            _ => Self::Other(method.to_string(), params),
+        })
+    }
+}
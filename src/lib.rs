@@ -1,13 +1,12 @@
 use std::{
+    collections::{HashMap, VecDeque},
     env,
-    num::ParseIntError,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
 };
 
-use jsonrpc_lite::{Id, JsonRpc};
 use once_cell::sync::Lazy;
 pub use psp_types;
 use psp_types::{
@@ -15,13 +14,21 @@ use psp_types::{
         notification::{LogMessage, ShowMessage},
         DocumentSelector, LogMessageParams, MessageType, ShowMessageParams, Url,
     },
-    ExecuteProcess, ExecuteProcessParams, ExecuteProcessResult, Notification, Request,
+    ExecuteProcess, ExecuteProcessParams, ExecuteProcessResult, LspId, Notification, Request,
     StartLspServer, StartLspServerParams, StartLspServerResult,
 };
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::Value;
 use thiserror::Error;
-use wasi_experimental_http::Response;
+use wasi_experimental_http::Response as HttpResponse;
+
+use crate::message::ResponseError;
+
+mod dispatch;
+mod message;
+pub mod lsp;
+
+pub use dispatch::{PluginNotification, PluginRequest};
 
 pub static PLUGIN_RPC: Lazy<PluginServerRpcHandler> = Lazy::new(PluginServerRpcHandler::new);
 
@@ -32,13 +39,11 @@ pub enum PluginError {
     #[error("HTTP related errors:{0}")]
     Http(#[from] http::Error),
     #[error("JSON-RPC related errors:{0}")]
-    JsonRpc(#[from] jsonrpc_lite::Error),
+    JsonRpc(#[from] ResponseError),
     #[error("I/O related errors:{0}")]
     Io(#[from] std::io::Error),
     #[error("Anyhow errors:{0}")]
     Anyhow(#[from] anyhow::Error),
-    #[error("Unable to parse string as number:{0}")]
-    ParseInt(#[from] ParseIntError),
     #[error("Other errors:{0}")]
     Other(String),
 }
@@ -81,8 +86,44 @@ impl VoltEnvironment {
 
 #[allow(unused_variables)]
 pub trait LapcePlugin {
-    fn handle_request(&mut self, id: u64, method: String, params: Value) {}
-    fn handle_notification(&mut self, method: String, params: Value) {}
+    /// Receives a request from the host as a raw `(method, params)` pair. The
+    /// default implementation runs it through `PluginRequest::parse` and
+    /// forwards the typed result to `handle_plugin_request`, answering the
+    /// host with an `invalid_params` error if parsing fails; override this
+    /// instead of `handle_plugin_request` if you need the raw method/params,
+    /// e.g. to respond differently to an unparseable request.
+    fn handle_request(&mut self, id: u64, method: String, params: Value) {
+        match PluginRequest::parse(&method, params) {
+            Ok(request) => self.handle_plugin_request(id, request),
+            Err(err) => {
+                let _ = PLUGIN_RPC.host_error(id, err.to_string());
+            }
+        }
+    }
+
+    /// Receives a request from the host already parsed into a `PluginRequest`
+    /// by the default `handle_request` implementation. Most plugins should
+    /// implement this rather than `handle_request`.
+    fn handle_plugin_request(&mut self, id: u64, request: PluginRequest) {}
+
+    /// Receives a notification from the host as a raw `(method, params)` pair.
+    /// The default implementation runs it through `PluginNotification::parse`
+    /// and forwards the typed result to `handle_plugin_notification`, logging
+    /// to stderr if parsing fails (notifications have no response to carry an
+    /// error back on); override this instead of `handle_plugin_notification`
+    /// if you need the raw method/params.
+    fn handle_notification(&mut self, method: String, params: Value) {
+        match PluginNotification::parse(&method, params) {
+            Ok(notification) => self.handle_plugin_notification(notification),
+            Err(err) => PLUGIN_RPC.stderr(&format!("failed to parse notification {method}: {err}")),
+        }
+    }
+
+    /// Receives a notification from the host already parsed into a
+    /// `PluginNotification` by the default `handle_notification`
+    /// implementation. Most plugins should implement this rather than
+    /// `handle_notification`.
+    fn handle_plugin_notification(&mut self, notification: PluginNotification) {}
 }
 
 pub enum PluginServerRpc {
@@ -97,14 +138,92 @@ pub enum PluginServerRpc {
     },
 }
 
+/// A request the host sent us that is still being serviced.
+struct IncomingRequest {
+    method: String,
+    /// Set once a `$/cancelRequest` for this id has been seen, so plugin
+    /// code can poll `PluginServerRpcHandler::is_cancelled` and bail out.
+    cancelled: bool,
+    /// Set once a response has actually been written to stdout for this id,
+    /// so a late `host_success`/`host_error` (e.g. racing an auto-cancel
+    /// response) can't write a second response for the same id.
+    answered: bool,
+}
+
+/// Bookkeeping for requests in flight in either direction, modeled on the
+/// `req_queue` used by the `lsp-server` crate.
+#[derive(Default)]
+struct ReqQueue {
+    /// Requests we sent to the host, keyed by id, along with their result
+    /// once the matching response has been read off stdin.
+    outgoing: HashMap<u64, Option<Result<Value, ResponseError>>>,
+    /// Requests the host sent us that are still being serviced, keyed by id,
+    /// so they can be answered (and cancelled) later.
+    incoming: HashMap<u64, IncomingRequest>,
+    /// Requests/notifications read from stdin but not yet handed out, e.g.
+    /// ones that arrived while a blocking `host_request` was waiting on its
+    /// own response. `parse_stdin` pops them one per call, oldest first.
+    buffered: VecDeque<PluginServerRpc>,
+}
+
 pub struct PluginServerRpcHandler {
     id: Arc<AtomicU64>,
+    req_queue: Arc<Mutex<ReqQueue>>,
+}
+
+/// A handle to a request this plugin sent to the host via `host_request_async`,
+/// letting the plugin either wait for the response or give up on it early by
+/// sending `$/cancelRequest`.
+pub struct CancelToken {
+    id: u64,
+}
+
+impl CancelToken {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Emits `$/cancelRequest` to the host for this request. Takes `self` by
+    /// value, like `wait`, so a cancelled token can't also be waited on:
+    /// `wait_for_response` would loop forever polling an `outgoing` slot that
+    /// `cancel` has already dropped from the map (see `Drop` below).
+    pub fn cancel(self) -> Result<(), PluginError> {
+        PLUGIN_RPC.host_notification(message::CANCEL_METHOD, message::CancelParams { id: self.id })
+    }
+
+    /// Blocks until the matching response arrives on stdin.
+    pub fn wait<D: DeserializeOwned>(self) -> Result<D, PluginError> {
+        PLUGIN_RPC.wait_for_response(self.id)
+    }
+}
+
+impl Drop for CancelToken {
+    /// Whether a `CancelToken` is waited on, cancelled, or simply dropped
+    /// (e.g. a fire-and-forget `host_request_async` call), its `outgoing`
+    /// entry must stop being tracked; otherwise an unawaited, uncancelled
+    /// token leaks the entry for the life of the process, and a reply
+    /// arriving afterwards writes into a slot nobody will ever read.
+    /// `forget_outgoing` is a no-op if `wait` already removed the entry, so
+    /// it's safe to always run here.
+    fn drop(&mut self) {
+        PLUGIN_RPC.forget_outgoing(self.id);
+    }
+}
+
+/// Envelope used to forward a request/notification to a specific LSP server
+/// the host is running on our behalf, identified by the `LspId` returned
+/// from `start_lsp`.
+#[derive(Serialize)]
+struct LspMessageParams<P> {
+    id: LspId,
+    method: String,
+    params: P,
 }
 
 pub struct Http {}
 
 impl Http {
-    pub fn get(url: &str) -> Result<Response, PluginError> {
+    pub fn get(url: &str) -> Result<HttpResponse, PluginError> {
         let req = http::request::Builder::new()
             .method(http::Method::GET)
             .uri(url)
@@ -131,6 +250,7 @@ macro_rules! register_plugin {
                         STATE.with(|state| {
                             state.borrow_mut().handle_request(id, method, params);
                         });
+                        $crate::PLUGIN_RPC.finish_request(id);
                     }
                     $crate::PluginServerRpc::Notification { method, params } => {
                         STATE.with(|state| {
@@ -147,6 +267,7 @@ impl PluginServerRpcHandler {
     fn new() -> Self {
         Self {
             id: Arc::new(AtomicU64::new(0)),
+            req_queue: Arc::new(Mutex::new(ReqQueue::default())),
         }
     }
 
@@ -203,29 +324,214 @@ impl PluginServerRpcHandler {
         )
     }
 
+    pub(crate) fn lsp_send_request_blocking<P: Serialize, D: DeserializeOwned>(
+        &self,
+        lsp_id: LspId,
+        method: &str,
+        params: P,
+    ) -> Result<D, PluginError> {
+        self.host_request(
+            "lsp_request",
+            LspMessageParams {
+                id: lsp_id,
+                method: method.to_string(),
+                params,
+            },
+        )
+    }
+
+    pub(crate) fn lsp_send_notification<P: Serialize>(
+        &self,
+        lsp_id: LspId,
+        method: &str,
+        params: P,
+    ) -> Result<(), PluginError> {
+        self.host_notification(
+            "lsp_notification",
+            LspMessageParams {
+                id: lsp_id,
+                method: method.to_string(),
+                params,
+            },
+        )
+    }
+
+    /// Sends a request to the host and blocks the current thread until the
+    /// matching response comes back on stdin. Any notification or request
+    /// read from the host in the meantime is not discarded: apart from a
+    /// `$/cancelRequest`, which is acted on at once, it is buffered
+    /// so the next `parse_stdin` call (driven by the host's `handle_rpc` up
+    /// call) can dispatch it normally, which makes this robust against the
+    /// host interleaving unrelated messages with our response.
     fn host_request<P: Serialize, D: DeserializeOwned>(
         &self,
         method: &str,
         params: P,
     ) -> Result<D, PluginError> {
+        self.host_request_async(method, params)?.wait()
+    }
+
+    /// Sends a request to the host without blocking, returning a `CancelToken`
+    /// that can later be used to either wait for the response or to emit a
+    /// `$/cancelRequest` for it.
+    pub fn host_request_async<P: Serialize>(
+        &self,
+        method: &str,
+        params: P,
+    ) -> Result<CancelToken, PluginError> {
         let id = self.id.fetch_add(1, Ordering::Relaxed);
         let params = serde_json::to_value(params)?;
+        self.req_queue.lock().unwrap().outgoing.insert(id, None);
         send_host_request(id, method, &params)?;
+        Ok(CancelToken { id })
+    }
+
+    /// Stops tracking an outgoing request. Called from `CancelToken`'s
+    /// `Drop` impl so that a token that's cancelled, waited on, or simply
+    /// dropped always has its `outgoing` entry cleaned up; a no-op if
+    /// `wait_for_response` already removed it.
+    fn forget_outgoing(&self, id: u64) {
+        self.req_queue.lock().unwrap().outgoing.remove(&id);
+    }
+
+    /// Blocks until the response to the outgoing request `id` has been read
+    /// off stdin.
+    fn wait_for_response<D: DeserializeOwned>(&self, id: u64) -> Result<D, PluginError> {
+        loop {
+            let ready = self
+                .req_queue
+                .lock()
+                .unwrap()
+                .outgoing
+                .get(&id)
+                .and_then(|slot| slot.clone());
+            if let Some(result) = ready {
+                self.req_queue.lock().unwrap().outgoing.remove(&id);
+                return match result {
+                    Ok(value) => Ok(serde_json::from_value::<D>(value)?),
+                    Err(err) => Err(PluginError::JsonRpc(err)),
+                };
+            }
+            self.read_and_dispatch_one()?;
+        }
+    }
+
+    /// Whether the host has sent a `$/cancelRequest` for the still-in-flight
+    /// incoming request `id`. Plugins servicing a long-running request should
+    /// poll this and stop work early when it returns `true`.
+    pub fn is_cancelled(&self, id: u64) -> bool {
+        self.req_queue
+            .lock()
+            .unwrap()
+            .incoming
+            .get(&id)
+            .map(|req| req.cancelled)
+            .unwrap_or(false)
+    }
+
+    /// Reads a single line from stdin and routes it: a response matching an
+    /// outstanding `outgoing` entry resolves that entry, a `$/cancelRequest`
+    /// notification is handled right away by `handle_cancel_request`, and any
+    /// other request or notification from the host is pushed onto the
+    /// buffered queue for `parse_stdin` to pick up.
+    fn read_and_dispatch_one(&self) -> Result<(), PluginError> {
         let mut msg = String::new();
         std::io::stdin().read_line(&mut msg)?;
-
-        match JsonRpc::parse(&msg) {
-            Ok(rpc) => {
-                if let Some(value) = rpc.get_result() {
-                    let result = serde_json::from_value::<D>(value.clone())?;
-                    Ok(result)
-                } else if let Some(err) = rpc.get_error() {
-                    Err(PluginError::JsonRpc(err.clone()))
-                } else {
-                    Err(PluginError::JsonRpc(jsonrpc_lite::Error::invalid_request()))
+        match serde_json::from_str::<message::Message>(&msg)? {
+            message::Message::Response(resp) => {
+                let result = match resp.error {
+                    Some(err) => Err(err),
+                    None => Ok(resp.result.unwrap_or(Value::Null)),
+                };
+                let mut req_queue = self.req_queue.lock().unwrap();
+                if let Some(slot) = req_queue.outgoing.get_mut(&resp.id) {
+                    *slot = Some(result);
                 }
             }
-            _ => Err(PluginError::JsonRpc(jsonrpc_lite::Error::invalid_request())),
+            message::Message::Request(req) => {
+                let mut req_queue = self.req_queue.lock().unwrap();
+                req_queue.incoming.insert(
+                    req.id,
+                    IncomingRequest {
+                        method: req.method.clone(),
+                        cancelled: false,
+                        answered: false,
+                    },
+                );
+                req_queue.buffered.push_back(PluginServerRpc::Request {
+                    id: req.id,
+                    method: req.method,
+                    params: req.params,
+                });
+            }
+            message::Message::Notification(note) if note.method == message::CANCEL_METHOD => {
+                self.handle_cancel_request(note.params)?;
+            }
+            message::Message::Notification(note) => {
+                self.req_queue
+                    .lock()
+                    .unwrap()
+                    .buffered
+                    .push_back(PluginServerRpc::Notification {
+                        method: note.method,
+                        params: note.params,
+                    });
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles an incoming `$/cancelRequest`: marks the referenced request as
+    /// cancelled (so `is_cancelled` keeps observing it even after this point)
+    /// and answers the host on its behalf with a JSON-RPC "request cancelled"
+    /// error, unless it has already been answered.
+    fn handle_cancel_request(&self, params: Value) -> Result<(), PluginError> {
+        let message::CancelParams { id } = serde_json::from_value(params)?;
+        let method = {
+            let mut req_queue = self.req_queue.lock().unwrap();
+            req_queue.incoming.get_mut(&id).map(|incoming| {
+                incoming.cancelled = true;
+                incoming.method.clone()
+            })
+        };
+        if let Some(method) = method {
+            self.stderr(&format!("cancelling request {id} ({method})"));
+            self.host_error_response(id, ResponseError::request_cancelled())?;
+        }
+        Ok(())
+    }
+
+    /// Marks incoming request `id` as answered if it hasn't been already,
+    /// returning whether the caller should go ahead and actually write a
+    /// response. This keeps an auto-cancel response and a later plugin
+    /// `host_success`/`host_error` call for the same id from both writing to
+    /// stdout.
+    fn mark_answered(&self, id: u64) -> bool {
+        let mut req_queue = self.req_queue.lock().unwrap();
+        match req_queue.incoming.get_mut(&id) {
+            Some(incoming) if !incoming.answered => {
+                incoming.answered = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Drops the `incoming` bookkeeping for `id` now that it has been fully
+    /// serviced, i.e. once `LapcePlugin::handle_request` has returned control
+    /// to `register_plugin!`'s dispatch loop.
+    pub fn finish_request(&self, id: u64) {
+        self.req_queue.lock().unwrap().incoming.remove(&id);
+    }
+
+    /// Pops the next buffered request/notification, reading and dispatching
+    /// stdin lines until one is available. Used by `parse_stdin`.
+    fn next_incoming(&self) -> Result<PluginServerRpc, PluginError> {
+        loop {
+            if let Some(rpc) = self.req_queue.lock().unwrap().buffered.pop_front() {
+                return Ok(rpc);
+            }
+            self.read_and_dispatch_one()?;
         }
     }
 
@@ -236,63 +542,31 @@ impl PluginServerRpcHandler {
     }
 
     pub fn host_success<P: Serialize>(&self, id: u64, params: P) -> Result<(), PluginError> {
+        if !self.mark_answered(id) {
+            return Ok(());
+        }
         let params = serde_json::to_value(params)?;
         send_host_success(id, &params)?;
         Ok(())
     }
 
     pub fn host_error<P: AsRef<str>>(&self, id: u64, params: P) -> Result<(), PluginError> {
-        send_host_error(id, params.as_ref())?;
-        Ok(())
+        self.host_error_response(id, ResponseError::invalid_params(params.as_ref()))
     }
-}
 
-fn number_from_id(id: &Id) -> Result<u64, PluginError> {
-    match *id {
-        Id::Num(n) => Ok(n as u64),
-        Id::Str(ref s) => Ok(s.parse::<u64>()?),
-        Id::None(_) => Err(PluginError::Other("id is not provided".to_string())),
+    /// Answers an incoming request `id` with an arbitrary JSON-RPC error,
+    /// unless it has already been answered (e.g. by an auto-cancel response).
+    fn host_error_response(&self, id: u64, error: ResponseError) -> Result<(), PluginError> {
+        if !self.mark_answered(id) {
+            return Ok(());
+        }
+        send_host_error(id, error)?;
+        Ok(())
     }
 }
 
 pub fn parse_stdin() -> Result<PluginServerRpc, PluginError> {
-    let mut msg = String::new();
-    std::io::stdin().read_line(&mut msg)?;
-    let rpc = match JsonRpc::parse(&msg) {
-        Ok(value @ JsonRpc::Request(_)) => {
-            let m_id = value
-                .get_id()
-                .ok_or(PluginError::Other("request is missing id".to_string()))?;
-            let id = number_from_id(&m_id)?;
-            PluginServerRpc::Request {
-                id,
-                method: value
-                    .get_method()
-                    .ok_or(PluginError::Other("request is missing method".to_string()))?
-                    .to_string(),
-                params: serde_json::to_value(
-                    value
-                        .get_params()
-                        .ok_or(PluginError::Other("request is missing params".to_string()))?,
-                )?,
-            }
-        }
-        Ok(value @ JsonRpc::Notification(_)) => PluginServerRpc::Notification {
-            method: value
-                .get_method()
-                .ok_or(PluginError::Other(
-                    "notification is missing method".to_string(),
-                ))?
-                .to_string(),
-            params: serde_json::to_value(value.get_params().ok_or(PluginError::Other(
-                "notification is missing params".to_string(),
-            ))?)?,
-        },
-        o => {
-            todo!("{:#?}", o)
-        }
-    };
-    Ok(rpc)
+    PLUGIN_RPC.next_incoming()
 }
 
 pub fn object_from_stdin<T: DeserializeOwned>() -> Result<T, PluginError> {
@@ -308,41 +582,25 @@ pub fn object_to_stdout(object: &impl Serialize) -> Result<(), PluginError> {
 }
 
 fn send_host_notification(method: &str, params: &Value) -> Result<(), PluginError> {
-    object_to_stdout(&serde_json::json!({
-        "jsonrpc": "2.0",
-        "method": method,
-        "params": params,
-    }))?;
+    object_to_stdout(&message::Notification::new(method, params.clone()))?;
     unsafe { host_handle_rpc() };
     Ok(())
 }
 
 fn send_host_request(id: u64, method: &str, params: &Value) -> Result<(), PluginError> {
-    object_to_stdout(&serde_json::json!({
-        "jsonrpc": "2.0",
-        "id": id,
-        "method": method,
-        "params": params,
-    }))?;
+    object_to_stdout(&message::Request::new(id, method, params.clone()))?;
     unsafe { host_handle_rpc() };
     Ok(())
 }
 
 fn send_host_success(id: u64, result: &Value) -> Result<(), PluginError> {
-    object_to_stdout(&jsonrpc_lite::JsonRpc::success(id as i64, result))?;
+    object_to_stdout(&message::Response::ok(id, result.clone()))?;
     unsafe { host_handle_rpc() };
     Ok(())
 }
 
-fn send_host_error(id: u64, message: &str) -> Result<(), PluginError> {
-    object_to_stdout(&jsonrpc_lite::JsonRpc::error(
-        id as i64,
-        jsonrpc_lite::Error {
-            code: jsonrpc_lite::ErrorCode::InvalidParams.code(),
-            message: message.to_string(),
-            data: None,
-        },
-    ))?;
+fn send_host_error(id: u64, error: ResponseError) -> Result<(), PluginError> {
+    object_to_stdout(&message::Response::err(id, error))?;
     unsafe { host_handle_rpc() };
     Ok(())
 }
@@ -12,6 +12,28 @@ impl LspRef {
         Self { id }
     }
 
+    /// Sends a typed request to this LSP server. The method string and the
+    /// params/result types come from `R`, so there is no way to pair the
+    /// wrong params or result type with a method.
+    pub fn request<R: psp_types::Request>(&self, params: R::Params) -> Result<R::Result, PluginError>
+    where
+        R::Params: Serialize,
+        R::Result: DeserializeOwned,
+    {
+        self.send_request_blocking(R::METHOD, params)
+    }
+
+    /// Sends a typed notification to this LSP server. The method string and
+    /// the params type come from `N`.
+    pub fn notify<N: psp_types::Notification>(&self, params: N::Params) -> Result<(), PluginError>
+    where
+        N::Params: Serialize,
+    {
+        self.send_notification(N::METHOD, params)
+    }
+
+    /// Escape hatch for methods not (yet) covered by `psp_types`: sends a
+    /// request with a loosely-typed method/params/result.
     pub fn send_request_blocking<P: Serialize, D: DeserializeOwned>(
         &self,
         method: &str,
@@ -20,6 +42,8 @@ impl LspRef {
         PLUGIN_RPC.lsp_send_request_blocking(self.id, method, params)
     }
 
+    /// Escape hatch for methods not (yet) covered by `psp_types`: sends a
+    /// notification with a loosely-typed method/params.
     pub fn send_notification<P: Serialize>(
         &self,
         method: &str,
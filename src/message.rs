@@ -0,0 +1,303 @@
+//! Lightweight JSON-RPC 2.0 message types.
+//!
+//! We used to parse everything through `jsonrpc_lite::JsonRpc`, but those
+//! types are stricter than the spec: `params` is required even though
+//! JSON-RPC 2.0 says it is optional, and plenty of language servers omit it
+//! or send fields we don't otherwise model. Helix ran into the same problem
+//! with `jsonrpc-core` (`deny_unknown_fields` rejected spec-violating
+//! servers) and dropped it in favor of its own types, which is the approach
+//! taken here: `params` defaults to `Value::Null`, no `deny_unknown_fields`,
+//! a single enum covers requests, notifications and responses (serialized
+//! untagged; see `Message` for how it is deserialized), and
+//! `id`s are accepted as either a JSON number or a numeric string (both are
+//! valid per the JSON-RPC 2.0 spec).
+
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+
+/// A single JSON-RPC message read from stdin (or written to stdout).
+///
+/// `Serialize` stays untagged (we only ever serialize one concrete variant,
+/// so there's no ambiguity), but `Deserialize` is hand-written below instead
+/// of relying on untagged's try-next-variant behavior: `Request` and
+/// `Response` both require an `id`, so if `id` fails to parse (e.g. a
+/// non-numeric string), untagged silently falls through to `Notification`
+/// instead of reporting the error, discarding the id and misrouting what was
+/// really a request. Deciding the variant explicitly up front from the
+/// presence of `method`/`id` avoids that.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Message {
+    Request(Request),
+    Response(Response),
+    Notification(Notification),
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct RawMessage {
+            #[serde(default)]
+            jsonrpc: String,
+            #[serde(default)]
+            id: Option<RawId>,
+            #[serde(default)]
+            method: Option<String>,
+            #[serde(default)]
+            params: Value,
+            #[serde(default)]
+            result: Option<Value>,
+            #[serde(default)]
+            error: Option<ResponseError>,
+        }
+
+        let raw = RawMessage::deserialize(deserializer)?;
+        let id = raw.id.map(RawId::into_u64::<D::Error>).transpose()?;
+        match (raw.method, id) {
+            (Some(method), Some(id)) => Ok(Message::Request(Request {
+                jsonrpc: raw.jsonrpc,
+                id,
+                method,
+                params: raw.params,
+            })),
+            (Some(method), None) => Ok(Message::Notification(Notification {
+                jsonrpc: raw.jsonrpc,
+                method,
+                params: raw.params,
+            })),
+            (None, Some(id)) => Ok(Message::Response(Response {
+                jsonrpc: raw.jsonrpc,
+                id,
+                result: raw.result,
+                error: raw.error,
+            })),
+            (None, None) => Err(D::Error::custom(
+                "JSON-RPC message has neither `method` nor `id`",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Request {
+    #[serde(default)]
+    pub jsonrpc: String,
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Notification {
+    #[serde(default)]
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Response {
+    #[serde(default)]
+    pub jsonrpc: String,
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<ResponseError>,
+}
+
+/// JSON-RPC 2.0 ids are allowed to be either a number or a string, and some
+/// servers send numbers as strings. We only ever hand out numeric ids
+/// ourselves (`PluginServerRpcHandler::host_request_async` uses an
+/// `AtomicU64` counter), so on the way in we accept either form and parse it
+/// down to a `u64`; this mirrors the old `jsonrpc_lite`-based code's
+/// `Id::Str(s) => s.parse()` fallback. A string id that isn't actually
+/// numeric is a message we can't correlate, so that's a deserialization
+/// error rather than a silent drop.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Number(u64),
+    String(String),
+}
+
+impl RawId {
+    fn into_u64<E: serde::de::Error>(self) -> Result<u64, E> {
+        match self {
+            RawId::Number(id) => Ok(id),
+            RawId::String(id) => id
+                .parse()
+                .map_err(|_| E::custom(format!("non-numeric JSON-RPC id: {id:?}"))),
+        }
+    }
+}
+
+fn deserialize_id<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    RawId::deserialize(deserializer)?.into_u64()
+}
+
+/// A JSON-RPC 2.0 error object.
+#[derive(Error, Debug, Clone, Serialize, Deserialize)]
+#[error("{message}")]
+pub struct ResponseError {
+    pub code: i64,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl ResponseError {
+    pub(crate) fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: -32602,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub(crate) fn request_cancelled() -> Self {
+        Self {
+            code: -32800,
+            message: "request cancelled".to_string(),
+            data: None,
+        }
+    }
+}
+
+impl Request {
+    pub(crate) fn new(id: u64, method: &str, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+impl Notification {
+    pub(crate) fn new(method: &str, params: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+/// The method name of the JSON-RPC meta-notification (borrowed from the LSP
+/// spec) used to cancel a previously sent request.
+pub(crate) const CANCEL_METHOD: &str = "$/cancelRequest";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CancelParams {
+    pub id: u64,
+}
+
+impl Response {
+    pub(crate) fn ok(id: u64, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub(crate) fn err(id: u64, error: ResponseError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_with_numeric_id() {
+        let msg: Message =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/foo","params":{}}"#)
+                .unwrap();
+        assert!(matches!(msg, Message::Request(req) if req.id == 1));
+    }
+
+    #[test]
+    fn request_with_numeric_string_id() {
+        let msg: Message =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","id":"1","method":"textDocument/foo"}"#)
+                .unwrap();
+        assert!(matches!(msg, Message::Request(req) if req.id == 1));
+    }
+
+    #[test]
+    fn request_with_non_numeric_string_id_is_a_hard_error() {
+        // Must not silently fall through to `Notification` and drop the id.
+        let err = serde_json::from_str::<Message>(
+            r#"{"jsonrpc":"2.0","id":"abc","method":"textDocument/foo","params":{}}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("non-numeric JSON-RPC id"));
+    }
+
+    #[test]
+    fn notification_has_no_id() {
+        let msg: Message =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","method":"textDocument/didOpen"}"#).unwrap();
+        assert!(matches!(msg, Message::Notification(note) if note.method == "textDocument/didOpen"));
+    }
+
+    #[test]
+    fn notification_without_params_defaults_to_null() {
+        let msg: Message =
+            serde_json::from_str(r#"{"jsonrpc":"2.0","method":"textDocument/didOpen"}"#).unwrap();
+        let Message::Notification(note) = msg else {
+            panic!("expected a notification");
+        };
+        assert_eq!(note.params, Value::Null);
+    }
+
+    #[test]
+    fn response_with_result() {
+        let msg: Message = serde_json::from_str(r#"{"jsonrpc":"2.0","id":1,"result":42}"#).unwrap();
+        assert!(matches!(msg, Message::Response(resp) if resp.result == Some(Value::from(42))));
+    }
+
+    #[test]
+    fn response_with_error() {
+        let msg: Message = serde_json::from_str(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32600,"message":"bad"}}"#,
+        )
+        .unwrap();
+        assert!(matches!(msg, Message::Response(resp) if resp.error.is_some()));
+    }
+
+    #[test]
+    fn message_with_neither_method_nor_id_is_an_error() {
+        serde_json::from_str::<Message>(r#"{"jsonrpc":"2.0"}"#).unwrap_err();
+    }
+
+    #[test]
+    fn extra_unknown_fields_are_tolerated() {
+        let msg: Message = serde_json::from_str(
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/foo","params":{},"extra":true}"#,
+        )
+        .unwrap();
+        assert!(matches!(msg, Message::Request(_)));
+    }
+}